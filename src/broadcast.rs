@@ -0,0 +1,103 @@
+// Implementation of broadcast, which writes the same plaintext to many connections.
+
+use std::io::ErrorKind::WriteZero;
+use std::mem;
+
+use futures_core::{Future, Poll};
+use futures_core::Async::{Ready, Pending};
+use futures_core::never::Never;
+use futures_core::task::Context;
+use futures_io::{AsyncWrite, Error};
+use box_stream::BoxDuplex;
+
+/// Create a future that writes `plaintext` to each of the `connections` and
+/// flushes them, all concurrently.
+///
+/// The plaintext is shared between all connections, but since every connection
+/// has its own keys, it is still encrypted separately for each of them.
+pub fn broadcast<'a, S: AsyncWrite>(connections: &'a mut [BoxDuplex<S>],
+                                    plaintext: &'a [u8])
+                                    -> Broadcast<'a, S> {
+    let progress = connections.iter().map(|_| Progress::Writing(0)).collect();
+    Broadcast {
+        connections,
+        plaintext,
+        progress,
+    }
+}
+
+/// A future that writes the same plaintext to many connections, created via
+/// [`broadcast`](fn.broadcast.html).
+pub struct Broadcast<'a, S: 'a> {
+    connections: &'a mut [BoxDuplex<S>],
+    plaintext: &'a [u8],
+    progress: Vec<Progress>,
+}
+
+enum Progress {
+    Writing(usize), // number of bytes of the plaintext written so far
+    Flushing,
+    Done(Result<(), Error>),
+    Taken,
+}
+
+impl<'a, S: AsyncWrite> Broadcast<'a, S> {
+    // Advance the write to the connection at index `i`, returning whether it is done.
+    fn poll_connection(&mut self, cx: &mut Context, i: usize) -> bool {
+        loop {
+            let next = match self.progress[i] {
+                Progress::Writing(offset) => {
+                    if offset == self.plaintext.len() {
+                        Progress::Flushing
+                    } else {
+                        match self.connections[i].poll_write(cx, &self.plaintext[offset..]) {
+                            Ok(Ready(0)) => {
+                                Progress::Done(Err(Error::new(WriteZero,
+                                                              "failed to write plaintext")))
+                            }
+                            Ok(Ready(written)) => Progress::Writing(offset + written),
+                            Ok(Pending) => return false,
+                            Err(e) => Progress::Done(Err(e)),
+                        }
+                    }
+                }
+                Progress::Flushing => {
+                    match self.connections[i].poll_flush(cx) {
+                        Ok(Ready(())) => Progress::Done(Ok(())),
+                        Ok(Pending) => return false,
+                        Err(e) => Progress::Done(Err(e)),
+                    }
+                }
+                Progress::Done(_) => return true,
+                Progress::Taken => panic!("Polled Broadcast after completion"),
+            };
+            self.progress[i] = next;
+        }
+    }
+}
+
+impl<'a, S: AsyncWrite> Future for Broadcast<'a, S> {
+    /// The outcome of the write for each connection, in the order of the
+    /// connections.
+    type Item = Vec<Result<(), Error>>;
+    type Error = Never;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        let mut done = true;
+        for i in 0..self.connections.len() {
+            done &= self.poll_connection(cx, i);
+        }
+
+        if !done {
+            return Ok(Pending);
+        }
+
+        Ok(Ready(self.progress
+                     .iter_mut()
+                     .map(|progress| match mem::replace(progress, Progress::Taken) {
+                              Progress::Done(result) => result,
+                              _ => unreachable!(),
+                          })
+                     .collect()))
+    }
+}
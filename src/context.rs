@@ -0,0 +1,66 @@
+// Implementation of ConnectionWithContext, a wrapper that attaches user data to a connection.
+
+use futures_core::Poll;
+use futures_core::task::Context;
+use futures_io::{Error, AsyncRead, AsyncWrite};
+
+/// Wraps a connection together with arbitrary user data, so that the data
+/// flows along with the connection. All reads and writes are passed through
+/// to the connection unchanged.
+pub struct ConnectionWithContext<S, T> {
+    inner: S,
+    context: T,
+}
+
+impl<S, T> ConnectionWithContext<S, T> {
+    /// Attach `context` to the connection `inner`.
+    pub fn new(inner: S, context: T) -> ConnectionWithContext<S, T> {
+        ConnectionWithContext { inner, context }
+    }
+
+    /// Gets a reference to the attached data.
+    pub fn context(&self) -> &T {
+        &self.context
+    }
+
+    /// Gets a mutable reference to the attached data.
+    pub fn context_mut(&mut self) -> &mut T {
+        &mut self.context
+    }
+
+    /// Gets a reference to the underlying connection.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying connection.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwraps this `ConnectionWithContext`, returning the underlying
+    /// connection and the attached data.
+    pub fn into_parts(self) -> (S, T) {
+        (self.inner, self.context)
+    }
+}
+
+impl<S: AsyncRead, T> AsyncRead for ConnectionWithContext<S, T> {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, Error> {
+        self.inner.poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite, T> AsyncWrite for ConnectionWithContext<S, T> {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<usize, Error> {
+        self.inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.inner.poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.inner.poll_close(cx)
+    }
+}
@@ -0,0 +1,50 @@
+// Implementation of crypto_info, which reports the primitive sizes this crate relies on.
+
+use sodiumoxide::crypto::{sign, box_, secretbox};
+use sodiumoxide::version;
+use secret_handshake::NETWORK_IDENTIFIER_BYTES;
+use box_stream::crypto::{CYPHER_HEADER_SIZE, MAX_PACKET_USIZE};
+
+/// The sizes of the cryptographic values used by this crate, and the version of
+/// the linked libsodium, as returned by [`crypto_info`](fn.crypto_info.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CryptoInfo {
+    /// Length of a network identifier in bytes.
+    pub network_identifier_bytes: usize,
+    /// Length of a longterm public key in bytes.
+    pub longterm_pk_bytes: usize,
+    /// Length of a longterm secret key in bytes.
+    pub longterm_sk_bytes: usize,
+    /// Length of an ephemeral public key in bytes.
+    pub ephemeral_pk_bytes: usize,
+    /// Length of an ephemeral secret key in bytes.
+    pub ephemeral_sk_bytes: usize,
+    /// Length of a box-stream key in bytes.
+    pub box_key_bytes: usize,
+    /// Length of a box-stream nonce in bytes.
+    pub box_nonce_bytes: usize,
+    /// Length of an encrypted box-stream header in bytes.
+    pub box_header_bytes: usize,
+    /// Maximum length of a box-stream body in bytes.
+    pub max_box_body_bytes: usize,
+    /// The version string of the libsodium this crate is linked against.
+    pub libsodium_version: &'static str,
+}
+
+/// Report the sizes of the cryptographic values used by this crate and the
+/// version of the linked libsodium, e.g. to check a build at startup or to
+/// include in bug reports.
+pub fn crypto_info() -> CryptoInfo {
+    CryptoInfo {
+        network_identifier_bytes: NETWORK_IDENTIFIER_BYTES,
+        longterm_pk_bytes: sign::PUBLICKEYBYTES,
+        longterm_sk_bytes: sign::SECRETKEYBYTES,
+        ephemeral_pk_bytes: box_::PUBLICKEYBYTES,
+        ephemeral_sk_bytes: box_::SECRETKEYBYTES,
+        box_key_bytes: secretbox::KEYBYTES,
+        box_nonce_bytes: secretbox::NONCEBYTES,
+        box_header_bytes: CYPHER_HEADER_SIZE,
+        max_box_body_bytes: MAX_PACKET_USIZE,
+        libsodium_version: version::version_string(),
+    }
+}
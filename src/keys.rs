@@ -0,0 +1,162 @@
+//! Helpers for working with the keys used in a handshake.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use sodiumoxide::crypto::{sign, box_};
+
+/// Check whether the longterm public key `pk` belongs to the longterm secret
+/// key `sk`.
+///
+/// Supplying keys that do not correspond only fails at handshake time, and
+/// then with an error on the *peer*, so this is worth checking when loading
+/// keys from configuration.
+pub fn validate_longterm(pk: &sign::PublicKey, sk: &sign::SecretKey) -> bool {
+    // A secret key consists of the seed followed by the public key, and libsodium
+    // uses the embedded public key when signing, so both halves must match.
+    if sk.0[sign::SEEDBYTES..] != pk.0[..] {
+        return false;
+    }
+
+    match sign::Seed::from_slice(&sk.0[..sign::SEEDBYTES]) {
+        Some(seed) => sign::keypair_from_seed(&seed).0 == *pk,
+        None => false,
+    }
+}
+
+/// Check whether the ephemeral public key `pk` belongs to the ephemeral secret
+/// key `sk`.
+pub fn validate_ephemeral(pk: &box_::PublicKey, sk: &box_::SecretKey) -> bool {
+    sk.public_key() == *pk
+}
+
+/// Deterministically derive a longterm keypair from a 32 byte `seed`, via
+/// `sodiumoxide::crypto::sign::keypair_from_seed`.
+///
+/// The same seed always yields the same keypair, so the seed must be kept
+/// exactly as secret as the secret key itself.
+pub fn longterm_from_seed(seed: &[u8; sign::SEEDBYTES]) -> (sign::PublicKey, sign::SecretKey) {
+    sign::keypair_from_seed(&sign::Seed(*seed))
+}
+
+/// An ephemeral keypair for a single handshake.
+///
+/// Reusing an ephemeral keypair for a second handshake silently breaks the
+/// forward secrecy of both connections. This type is neither `Clone` nor
+/// `Copy` and is consumed by the constructors that take it, so passing the
+/// same `EphemeralKeypair` to two handshakes is a compile error. This does not
+/// stop a caller from wrapping the same keys twice via
+/// [`from_keys`](#method.from_keys), so keys passed to it must not be used
+/// anywhere else.
+///
+/// ```compile_fail
+/// # extern crate futures_io;
+/// # extern crate secret_stream;
+/// # extern crate sodiumoxide;
+/// use futures_io::{AsyncRead, AsyncWrite};
+/// use secret_stream::OwningServer;
+/// use secret_stream::keys::EphemeralKeypair;
+/// use sodiumoxide::crypto::sign;
+///
+/// fn accept_two<S: AsyncRead + AsyncWrite>(a: S,
+///                                          b: S,
+///                                          pk: sign::PublicKey,
+///                                          sk: sign::SecretKey) {
+///     let ephemeral = EphemeralKeypair::generate();
+///     let _ = OwningServer::new_with_ephemeral_keypair(a, [0; 32], pk, sk.clone(), ephemeral);
+///     let _ = OwningServer::new_with_ephemeral_keypair(b, [0; 32], pk, sk, ephemeral);
+/// }
+/// # fn main() {}
+/// ```
+pub struct EphemeralKeypair {
+    pk: box_::PublicKey,
+    sk: box_::SecretKey,
+}
+
+impl EphemeralKeypair {
+    /// Generate a fresh ephemeral keypair via
+    /// `sodiumoxide::crypto::box_::gen_keypair`.
+    pub fn generate() -> EphemeralKeypair {
+        let (pk, sk) = box_::gen_keypair();
+        EphemeralKeypair { pk, sk }
+    }
+
+    /// Wrap an existing ephemeral keypair, e.g. one produced by a custom
+    /// [`EphemeralSource`](trait.EphemeralSource.html).
+    ///
+    /// This does not check whether `pk` belongs to `sk`, use
+    /// [`validate_ephemeral`](fn.validate_ephemeral.html) for that.
+    pub fn from_keys(pk: box_::PublicKey, sk: box_::SecretKey) -> EphemeralKeypair {
+        EphemeralKeypair { pk, sk }
+    }
+
+    /// The public key of this keypair.
+    pub fn public_key(&self) -> &box_::PublicKey {
+        &self.pk
+    }
+
+    pub(crate) fn into_keys(self) -> (box_::PublicKey, box_::SecretKey) {
+        (self.pk, self.sk)
+    }
+}
+
+/// A source of ephemeral keypairs, queried once per handshake by the
+/// `new_with_ephemeral_source` constructors.
+///
+/// This allows centralizing ephemeral key generation, e.g. to check the health
+/// of the random number generator or to rate-limit key generation.
+pub trait EphemeralSource {
+    /// Produce a fresh ephemeral keypair for the next handshake.
+    fn next(&mut self) -> EphemeralKeypair;
+}
+
+/// The default [`EphemeralSource`](trait.EphemeralSource.html), which generates
+/// keypairs via `sodiumoxide::crypto::box_::gen_keypair`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenKeypair;
+
+impl EphemeralSource for GenKeypair {
+    fn next(&mut self) -> EphemeralKeypair {
+        EphemeralKeypair::generate()
+    }
+}
+
+/// Identifies a key given as raw bytes that has the wrong length, or a pair
+/// of keys that do not belong together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyError {
+    /// Our own longterm public key is malformed.
+    LongtermPk,
+    /// Our own longterm secret key is malformed.
+    LongtermSk,
+    /// Our own ephemeral public key is malformed.
+    EphemeralPk,
+    /// Our own ephemeral secret key is malformed.
+    EphemeralSk,
+    /// The peer's longterm public key is malformed.
+    PeerLongtermPk,
+    /// Our own longterm public key does not belong to our longterm secret key.
+    LongtermMismatch,
+    /// Our own ephemeral public key does not belong to our ephemeral secret key.
+    EphemeralMismatch,
+}
+
+impl Display for KeyError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            KeyError::LongtermPk => write!(f, "Key error: malformed longterm public key"),
+            KeyError::LongtermSk => write!(f, "Key error: malformed longterm secret key"),
+            KeyError::EphemeralPk => write!(f, "Key error: malformed ephemeral public key"),
+            KeyError::EphemeralSk => write!(f, "Key error: malformed ephemeral secret key"),
+            KeyError::PeerLongtermPk => write!(f, "Key error: malformed peer longterm public key"),
+            KeyError::LongtermMismatch => {
+                write!(f, "Key error: longterm public key does not belong to secret key")
+            }
+            KeyError::EphemeralMismatch => {
+                write!(f, "Key error: ephemeral public key does not belong to secret key")
+            }
+        }
+    }
+}
+
+impl Error for KeyError {}
@@ -3,6 +3,50 @@
 //! This library uses libsodium internally. In application code, call
 //! [`sodiumoxide::init()`](https://dnaq.github.io/sodiumoxide/sodiumoxide/fn.init.html)
 //! before using any functions from this module.
+//!
+//! The box-stream types are re-exported, so [`BoxWriter`](struct.BoxWriter.html)
+//! and [`BoxReader`](struct.BoxReader.html) can be used to encrypt or decrypt
+//! with keys and nonces obtained elsewhere, without running a handshake.
+//!
+//! # Upgrading a plaintext connection
+//!
+//! The handshake can be started on a stream that has already been used for
+//! plaintext communication, e.g. after a higher-level protocol negotiated to
+//! switch to encryption (similar to STARTTLS): simply pass the open stream to
+//! one of the constructors at that point. No plaintext bytes may remain
+//! buffered between the peers when doing so, so any reader buffering the
+//! stream must be drained (and unwrapped) first, and the peer must not send
+//! further plaintext after agreeing to upgrade.
+//!
+//! ```no_run
+//! # extern crate futures_io;
+//! # extern crate secret_stream;
+//! # extern crate sodiumoxide;
+//! use futures_io::{AsyncRead, AsyncWrite};
+//! use secret_stream::{network_identifier_from_hex, OwningClient};
+//! use sodiumoxide::crypto::{box_, sign};
+//!
+//! const NETWORK_IDENTIFIER: [u8; 32] =
+//!     network_identifier_from_hex("d4a1cb88a66f02f8db635ce26441cc5dac1b08420ceaac230839b755845a9ffb");
+//!
+//! // Called once the plaintext protocol on `stream` has agreed to switch to
+//! // encryption, with no plaintext left buffered on either side.
+//! fn upgrade<S: AsyncRead + AsyncWrite>(stream: S,
+//!                                       client_pk: sign::PublicKey,
+//!                                       client_sk: sign::SecretKey,
+//!                                       server_pk: sign::PublicKey)
+//!                                       -> OwningClient<S> {
+//!     let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+//!     OwningClient::new(stream,
+//!                       NETWORK_IDENTIFIER,
+//!                       client_pk,
+//!                       client_sk,
+//!                       ephemeral_pk,
+//!                       ephemeral_sk,
+//!                       server_pk)
+//! }
+//! # fn main() {}
+//! ```
 
 #![deny(missing_docs)]
 
@@ -13,6 +57,8 @@ extern crate futures_core;
 extern crate futures_io;
 extern crate sodiumoxide;
 
+use std::mem::ManuallyDrop;
+
 use futures_core::{Future, Poll};
 use futures_core::Async::Ready;
 use futures_core::task::Context;
@@ -20,7 +66,82 @@ use futures_io::{AsyncRead, AsyncWrite};
 use sodiumoxide::crypto::{sign, box_};
 use secret_handshake::*;
 use secret_handshake::errors::*;
-use box_stream::*;
+pub use box_stream::{BoxDuplex, BoxReader, BoxWriter};
+
+pub mod keys;
+mod broadcast;
+mod context;
+mod info;
+mod peek;
+mod registry;
+
+pub use broadcast::{broadcast, Broadcast};
+pub use context::ConnectionWithContext;
+pub use info::{crypto_info, CryptoInfo};
+pub use peek::{peek_first_message, PeekFirstMessage, FirstMessageInfo, Replay};
+pub use registry::{TenantRegistry, RegistryServer, RegistryError};
+
+#[cfg(test)]
+mod test;
+
+/// The number of bytes box-stream adds to each frame: the encrypted header,
+/// containing the body length and the MACs of header and body.
+///
+/// A frame consisting of `n` bytes of plaintext (at most 4096) takes
+/// `FRAME_OVERHEAD + n` bytes on the wire. Closing a stream writes one more
+/// `FRAME_OVERHEAD`-sized goodbye header, which has no body.
+pub const FRAME_OVERHEAD: usize = box_stream::crypto::CYPHER_HEADER_SIZE;
+
+/// The number of plaintext bytes that fit into a single frame of at most
+/// `frame_size` bytes on the wire.
+///
+/// This is `frame_size - FRAME_OVERHEAD`, but never more than the 4096 bytes
+/// box-stream allows per frame body, and zero if `frame_size` can not even
+/// hold the header.
+pub const fn max_plaintext_per_frame(frame_size: usize) -> usize {
+    let available = frame_size.saturating_sub(FRAME_OVERHEAD);
+    if available < box_stream::crypto::MAX_PACKET_USIZE {
+        available
+    } else {
+        box_stream::crypto::MAX_PACKET_USIZE
+    }
+}
+
+/// Parse a network identifier from a string of `2 * NETWORK_IDENTIFIER_BYTES`
+/// hex digits.
+///
+/// This panics if `hex` has the wrong length or contains anything but hex
+/// digits. When used to initialize a `const`, such a mistake is a compile error
+/// instead:
+///
+/// ```compile_fail
+/// # extern crate secret_stream;
+/// # use secret_stream::network_identifier_from_hex;
+/// const TOO_SHORT: [u8; 32] = network_identifier_from_hex("d4a1cb88a66f02f8");
+/// # fn main() {}
+/// ```
+pub const fn network_identifier_from_hex(hex: &str) -> [u8; NETWORK_IDENTIFIER_BYTES] {
+    let hex = hex.as_bytes();
+    assert!(hex.len() == 2 * NETWORK_IDENTIFIER_BYTES,
+            "network identifier must consist of 64 hex digits");
+
+    let mut network_identifier = [0; NETWORK_IDENTIFIER_BYTES];
+    let mut i = 0;
+    while i < NETWORK_IDENTIFIER_BYTES {
+        network_identifier[i] = (hex_digit(hex[2 * i]) << 4) | hex_digit(hex[2 * i + 1]);
+        i += 1;
+    }
+    network_identifier
+}
+
+const fn hex_digit(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        b'A'..=b'F' => digit - b'A' + 10,
+        _ => panic!("network identifier contains a non-hex digit"),
+    }
+}
 
 /// A future that initiates a secret-handshake and then yields a channel that
 /// encrypts/decrypts all data via box-stream.
@@ -64,6 +185,73 @@ impl<'a, S: AsyncRead + AsyncWrite> Future for Client<'a, S> {
     }
 }
 
+/// A future that initiates a secret-handshake and then yields a channel that
+/// encrypts/decrypts all data via box-stream.
+///
+/// This works like a [`Client`](struct.Client.html), but owns the network
+/// identifier, so that it does not need to outlive the handshake. The keys
+/// are still borrowed.
+pub struct ClientOwningNetworkIdentifier<'a, S> {
+    // Borrows `*network_identifier`, so it is dropped first (see the `Drop` impl).
+    inner: ManuallyDrop<Client<'a, S>>,
+    // Allocated via `Box::into_raw`, freed in `drop`.
+    network_identifier: *mut [u8; NETWORK_IDENTIFIER_BYTES],
+}
+
+impl<'a, S: AsyncRead + AsyncWrite> ClientOwningNetworkIdentifier<'a, S> {
+    /// Create a new `ClientOwningNetworkIdentifier` to connect to a server with
+    /// known public key and app key over the given `stream`.
+    ///
+    /// Ephemeral keypairs can be generated via
+    /// `sodiumoxide::crypto::box_::gen_keypair`.
+    pub fn new(stream: S,
+               network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+               client_longterm_pk: &'a sign::PublicKey,
+               client_longterm_sk: &'a sign::SecretKey,
+               client_ephemeral_pk: &'a box_::PublicKey,
+               client_ephemeral_sk: &'a box_::SecretKey,
+               server_longterm_pk: &'a sign::PublicKey)
+               -> ClientOwningNetworkIdentifier<'a, S> {
+        let network_identifier = Box::into_raw(Box::new(network_identifier));
+        // SAFETY: `network_identifier` comes from `Box::into_raw`, so it is valid
+        // and stays at the same address until it is freed in `drop`. It is only
+        // accessed through shared references, and `drop` frees it only after
+        // `inner`, the sole holder of the reference, has been dropped.
+        let network_identifier_ref = unsafe { &*network_identifier };
+        ClientOwningNetworkIdentifier {
+            inner: ManuallyDrop::new(Client::new(stream,
+                                                 network_identifier_ref,
+                                                 client_longterm_pk,
+                                                 client_longterm_sk,
+                                                 client_ephemeral_pk,
+                                                 client_ephemeral_sk,
+                                                 server_longterm_pk)),
+            network_identifier,
+        }
+    }
+}
+
+impl<'a, S> Drop for ClientOwningNetworkIdentifier<'a, S> {
+    fn drop(&mut self) {
+        // SAFETY: `inner` is never used again, and dropping it first ends the only
+        // borrow of `network_identifier`, which was allocated via `Box::into_raw`
+        // in `new` and is freed exactly once here.
+        unsafe {
+            ManuallyDrop::drop(&mut self.inner);
+            drop(Box::from_raw(self.network_identifier));
+        }
+    }
+}
+
+impl<'a, S: AsyncRead + AsyncWrite> Future for ClientOwningNetworkIdentifier<'a, S> {
+    type Item = BoxDuplex<S>;
+    type Error = (HandshakeError, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        self.inner.poll(cx)
+    }
+}
+
 /// A future that initiates a secret-handshake and then yields a channel that
 /// encrypts/decrypts all data via box-stream.
 ///
@@ -94,6 +282,78 @@ impl<S: AsyncRead + AsyncWrite> OwningClient<S> {
                                                  client_ephemeral_sk,
                                                  server_longterm_pk))
     }
+
+    /// Create a new `OwningClient` like [`new`](#method.new), but consume an
+    /// [`EphemeralKeypair`](keys/struct.EphemeralKeypair.html), so that the
+    /// ephemeral keys can not accidentally be reused for another handshake.
+    pub fn new_with_ephemeral_keypair(stream: S,
+                                      network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                                      client_longterm_pk: sign::PublicKey,
+                                      client_longterm_sk: sign::SecretKey,
+                                      client_ephemeral: keys::EphemeralKeypair,
+                                      server_longterm_pk: sign::PublicKey)
+                                      -> OwningClient<S> {
+        let (client_ephemeral_pk, client_ephemeral_sk) = client_ephemeral.into_keys();
+        OwningClient::new(stream,
+                          network_identifier,
+                          client_longterm_pk,
+                          client_longterm_sk,
+                          client_ephemeral_pk,
+                          client_ephemeral_sk,
+                          server_longterm_pk)
+    }
+
+    /// Create a new `OwningClient` like [`new`](#method.new), but take the keys
+    /// as raw bytes, e.g. as loaded from a configuration file or received over
+    /// FFI.
+    ///
+    /// If any of the keys has the wrong length, or if a public key does not
+    /// belong to the corresponding secret key, this returns an error
+    /// identifying the problem, together with the `stream`.
+    pub fn new_from_bytes(stream: S,
+                          network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                          client_longterm_pk: &[u8],
+                          client_longterm_sk: &[u8],
+                          client_ephemeral_pk: &[u8],
+                          client_ephemeral_sk: &[u8],
+                          server_longterm_pk: &[u8])
+                          -> Result<OwningClient<S>, (keys::KeyError, S)> {
+        let client_longterm_pk = match sign::PublicKey::from_slice(client_longterm_pk) {
+            Some(key) => key,
+            None => return Err((keys::KeyError::LongtermPk, stream)),
+        };
+        let client_longterm_sk = match sign::SecretKey::from_slice(client_longterm_sk) {
+            Some(key) => key,
+            None => return Err((keys::KeyError::LongtermSk, stream)),
+        };
+        let client_ephemeral_pk = match box_::PublicKey::from_slice(client_ephemeral_pk) {
+            Some(key) => key,
+            None => return Err((keys::KeyError::EphemeralPk, stream)),
+        };
+        let client_ephemeral_sk = match box_::SecretKey::from_slice(client_ephemeral_sk) {
+            Some(key) => key,
+            None => return Err((keys::KeyError::EphemeralSk, stream)),
+        };
+        let server_longterm_pk = match sign::PublicKey::from_slice(server_longterm_pk) {
+            Some(key) => key,
+            None => return Err((keys::KeyError::PeerLongtermPk, stream)),
+        };
+
+        if !keys::validate_longterm(&client_longterm_pk, &client_longterm_sk) {
+            return Err((keys::KeyError::LongtermMismatch, stream));
+        }
+        if !keys::validate_ephemeral(&client_ephemeral_pk, &client_ephemeral_sk) {
+            return Err((keys::KeyError::EphemeralMismatch, stream));
+        }
+
+        Ok(OwningClient::new(stream,
+                             network_identifier,
+                             client_longterm_pk,
+                             client_longterm_sk,
+                             client_ephemeral_pk,
+                             client_ephemeral_sk,
+                             server_longterm_pk))
+    }
 }
 
 impl<S: AsyncRead + AsyncWrite> Future for OwningClient<S> {
@@ -132,8 +392,8 @@ impl<'a, S: AsyncRead + AsyncWrite> Server<'a, S> {
                                      network_identifier,
                                      server_longterm_pk,
                                      server_longterm_sk,
-                                     &server_ephemeral_pk,
-                                     &server_ephemeral_sk))
+                                     server_ephemeral_pk,
+                                     server_ephemeral_sk))
     }
 }
 
@@ -183,6 +443,41 @@ impl<S: AsyncRead + AsyncWrite> OwningServer<S> {
                                                  server_ephemeral_pk,
                                                  server_ephemeral_sk))
     }
+
+    /// Create a new `OwningServer` like [`new`](#method.new), but consume an
+    /// [`EphemeralKeypair`](keys/struct.EphemeralKeypair.html), so that the
+    /// ephemeral keys can not accidentally be reused for another handshake.
+    pub fn new_with_ephemeral_keypair(stream: S,
+                                      network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                                      server_longterm_pk: sign::PublicKey,
+                                      server_longterm_sk: sign::SecretKey,
+                                      server_ephemeral: keys::EphemeralKeypair)
+                                      -> OwningServer<S> {
+        let (server_ephemeral_pk, server_ephemeral_sk) = server_ephemeral.into_keys();
+        OwningServer::new(stream,
+                          network_identifier,
+                          server_longterm_pk,
+                          server_longterm_sk,
+                          server_ephemeral_pk,
+                          server_ephemeral_sk)
+    }
+
+    /// Create a new `OwningServer` like [`new`](#method.new), but take the
+    /// ephemeral keypair from `source`, which is queried exactly once.
+    pub fn new_with_ephemeral_source<Source>(stream: S,
+                                             network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                                             server_longterm_pk: sign::PublicKey,
+                                             server_longterm_sk: sign::SecretKey,
+                                             source: &mut Source)
+                                             -> OwningServer<S>
+        where Source: keys::EphemeralSource
+    {
+        OwningServer::new_with_ephemeral_keypair(stream,
+                                                 network_identifier,
+                                                 server_longterm_pk,
+                                                 server_longterm_sk,
+                                                 source.next())
+    }
 }
 
 impl<S: AsyncRead + AsyncWrite> Future for OwningServer<S> {
@@ -202,6 +497,28 @@ impl<S: AsyncRead + AsyncWrite> Future for OwningServer<S> {
     }
 }
 
+/// A boxed future that resolves to whether a client should be accepted.
+///
+/// This does not require `Send`, so that filters can use e.g. `Rc`-based
+/// state. Requiring it would gain nothing, since the handshake futures refer to
+/// the keys via raw pointers and are therefore not `Send` themselves. Filters
+/// of any type, `Send` or not, can be used via the generic `new` constructors.
+pub type BoxedAsyncBool<E> = Box<dyn Future<Item = bool, Error = E>>;
+
+/// A boxed filter function, for use with [`ServerFilter::new_boxed`](struct.ServerFilter.html#method.new_boxed)
+/// and [`OwningServerFilter::new_boxed`](struct.OwningServerFilter.html#method.new_boxed).
+pub type BoxedFilterFn<E> = Box<dyn FnOnce(&sign::PublicKey) -> BoxedAsyncBool<E>>;
+
+/// A `ServerFilter` with a boxed filter function, so that its type does not
+/// depend on the filter function.
+pub type BoxedServerFilter<'a, S, E> = ServerFilter<'a, S, BoxedFilterFn<E>, BoxedAsyncBool<E>>;
+
+/// An `OwningServerFilter` with a boxed filter function, so that its type does
+/// not depend on the filter function.
+pub type BoxedOwningServerFilter<S, E> = OwningServerFilter<S,
+                                                            BoxedFilterFn<E>,
+                                                            BoxedAsyncBool<E>>;
+
 /// A future that accepts a secret-handshake based on a filter function and then
 /// yields a channel that encrypts/decrypts all data via box-stream.
 pub struct ServerFilter<'a, S, FilterFn, AsyncBool>(ServerHandshakerWithFilter<'a,
@@ -233,8 +550,30 @@ impl<'a, S, FilterFn, AsyncBool> ServerFilter<'a, S, FilterFn, AsyncBool>
                                                      network_identifier,
                                                      server_longterm_pk,
                                                      server_longterm_sk,
-                                                     &server_ephemeral_pk,
-                                                     &server_ephemeral_sk))
+                                                     server_ephemeral_pk,
+                                                     server_ephemeral_sk))
+    }
+}
+
+impl<'a, S: AsyncRead + AsyncWrite, E> BoxedServerFilter<'a, S, E> {
+    /// Create a new `ServerFilter` like [`new`](#method.new), but with a boxed
+    /// filter function. This trades dynamic dispatch for a type that can easily
+    /// be stored, e.g. in a collection of heterogeneous servers.
+    pub fn new_boxed(stream: S,
+                     filter_fn: BoxedFilterFn<E>,
+                     network_identifier: &'a [u8; NETWORK_IDENTIFIER_BYTES],
+                     server_longterm_pk: &'a sign::PublicKey,
+                     server_longterm_sk: &'a sign::SecretKey,
+                     server_ephemeral_pk: &'a box_::PublicKey,
+                     server_ephemeral_sk: &'a box_::SecretKey)
+                     -> BoxedServerFilter<'a, S, E> {
+        ServerFilter::new(stream,
+                          filter_fn,
+                          network_identifier,
+                          server_longterm_pk,
+                          server_longterm_sk,
+                          server_ephemeral_pk,
+                          server_ephemeral_sk)
     }
 }
 
@@ -296,6 +635,67 @@ impl<S, FilterFn, AsyncBool> OwningServerFilter<S, FilterFn, AsyncBool>
                                                                  server_ephemeral_pk,
                                                                  server_ephemeral_sk))
     }
+
+    /// Create a new `OwningServerFilter` like [`new`](#method.new), but consume
+    /// an [`EphemeralKeypair`](keys/struct.EphemeralKeypair.html), so that the
+    /// ephemeral keys can not accidentally be reused for another handshake.
+    pub fn new_with_ephemeral_keypair(stream: S,
+                                      filter_fn: FilterFn,
+                                      network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                                      server_longterm_pk: sign::PublicKey,
+                                      server_longterm_sk: sign::SecretKey,
+                                      server_ephemeral: keys::EphemeralKeypair)
+                                      -> OwningServerFilter<S, FilterFn, AsyncBool> {
+        let (server_ephemeral_pk, server_ephemeral_sk) = server_ephemeral.into_keys();
+        OwningServerFilter::new(stream,
+                                filter_fn,
+                                network_identifier,
+                                server_longterm_pk,
+                                server_longterm_sk,
+                                server_ephemeral_pk,
+                                server_ephemeral_sk)
+    }
+
+    /// Create a new `OwningServerFilter` like [`new`](#method.new), but take the
+    /// ephemeral keypair from `source`, which is queried exactly once.
+    pub fn new_with_ephemeral_source<Source>(stream: S,
+                                             filter_fn: FilterFn,
+                                             network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                                             server_longterm_pk: sign::PublicKey,
+                                             server_longterm_sk: sign::SecretKey,
+                                             source: &mut Source)
+                                             -> OwningServerFilter<S, FilterFn, AsyncBool>
+        where Source: keys::EphemeralSource
+    {
+        OwningServerFilter::new_with_ephemeral_keypair(stream,
+                                                       filter_fn,
+                                                       network_identifier,
+                                                       server_longterm_pk,
+                                                       server_longterm_sk,
+                                                       source.next())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite, E> BoxedOwningServerFilter<S, E> {
+    /// Create a new `OwningServerFilter` like [`new`](#method.new), but with a
+    /// boxed filter function. This trades dynamic dispatch for a type that can
+    /// easily be stored, e.g. in a collection of heterogeneous servers.
+    pub fn new_boxed(stream: S,
+                     filter_fn: BoxedFilterFn<E>,
+                     network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                     server_longterm_pk: sign::PublicKey,
+                     server_longterm_sk: sign::SecretKey,
+                     server_ephemeral_pk: box_::PublicKey,
+                     server_ephemeral_sk: box_::SecretKey)
+                     -> BoxedOwningServerFilter<S, E> {
+        OwningServerFilter::new(stream,
+                                filter_fn,
+                                network_identifier,
+                                server_longterm_pk,
+                                server_longterm_sk,
+                                server_ephemeral_pk,
+                                server_ephemeral_sk)
+    }
 }
 
 impl<S, FilterFn, AsyncBool> Future for OwningServerFilter<S, FilterFn, AsyncBool>
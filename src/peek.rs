@@ -0,0 +1,162 @@
+// Implementation of peek_first_message, which reads the first handshake message
+// of a client and hands out a stream that replays it.
+
+use std::cmp::min;
+use std::io::ErrorKind::UnexpectedEof;
+
+use futures_core::{Future, Poll};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{Error, AsyncRead, AsyncWrite};
+use sodiumoxide::crypto::{auth, box_};
+use secret_handshake::NETWORK_IDENTIFIER_BYTES;
+use secret_handshake::crypto::MSG1_BYTES;
+
+/// Create a future that reads the first handshake message of a client from
+/// `stream`, e.g. so that a load balancer can route the connection.
+///
+/// The future yields what can be learned from the message, and a stream that
+/// replays the message before continuing with `stream`, so that a server
+/// handshake on it proceeds as if the message had never been read.
+///
+/// If the future fails, it yields the error together with `stream`, but the
+/// bytes of the message that were already read are lost, so the stream can
+/// not be used for a handshake anymore.
+pub fn peek_first_message<S: AsyncRead>(stream: S) -> PeekFirstMessage<S> {
+    PeekFirstMessage {
+        stream: Some(stream),
+        data: [0; MSG1_BYTES],
+        offset: 0,
+    }
+}
+
+/// A future that reads the first handshake message of a client, created via
+/// [`peek_first_message`](fn.peek_first_message.html).
+pub struct PeekFirstMessage<S> {
+    stream: Option<S>,
+    data: [u8; MSG1_BYTES],
+    offset: usize,
+}
+
+impl<S: AsyncRead> Future for PeekFirstMessage<S> {
+    type Item = (FirstMessageInfo, Replay<S>);
+    type Error = (Error, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        let mut stream = self.stream
+            .take()
+            .expect("Polled PeekFirstMessage after completion");
+
+        while self.offset < MSG1_BYTES {
+            match stream.poll_read(cx, &mut self.data[self.offset..]) {
+                Ok(Ready(0)) => {
+                    return Err((Error::new(UnexpectedEof, "failed to read msg1"), stream))
+                }
+                Ok(Ready(read)) => self.offset += read,
+                Ok(Pending) => {
+                    self.stream = Some(stream);
+                    return Ok(Pending);
+                }
+                Err(e) => return Err((e, stream)),
+            }
+        }
+
+        let info = FirstMessageInfo {
+            mac: auth::Tag::from_slice(&self.data[..auth::TAGBYTES]).unwrap(),
+            client_ephemeral_pk: box_::PublicKey::from_slice(&self.data[auth::TAGBYTES..])
+                .unwrap(),
+        };
+        Ok(Ready((info,
+                  Replay {
+                      inner: stream,
+                      data: self.data,
+                      offset: 0,
+                  })))
+    }
+}
+
+/// What can be learned from the first handshake message of a client without
+/// knowing any keys of the server.
+///
+/// The message consists of the client's ephemeral public key and an HMAC of
+/// that key, keyed with the network identifier. So the network identifier is
+/// not transmitted, but the message can be checked against any known network
+/// identifier. Nothing about the longterm identities of either peer is revealed.
+#[derive(Debug, Clone)]
+pub struct FirstMessageInfo {
+    mac: auth::Tag,
+    client_ephemeral_pk: box_::PublicKey,
+}
+
+impl FirstMessageInfo {
+    /// The ephemeral public key of the client.
+    pub fn client_ephemeral_pk(&self) -> &box_::PublicKey {
+        &self.client_ephemeral_pk
+    }
+
+    /// Check whether the client created the message for the given network
+    /// identifier.
+    pub fn matches_network_identifier(&self,
+                                      network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES])
+                                      -> bool {
+        auth::verify(&self.mac,
+                     &self.client_ephemeral_pk.0,
+                     &auth::Key(*network_identifier))
+    }
+}
+
+/// Wraps a stream from which the first handshake message has been read, and
+/// replays that message before reading from the stream again. Writes go to the
+/// stream directly.
+pub struct Replay<S> {
+    inner: S,
+    data: [u8; MSG1_BYTES],
+    offset: usize, // number of bytes of `data` that have been replayed
+}
+
+impl<S> Replay<S> {
+    /// Gets a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying stream.
+    ///
+    /// It is inadvisable to directly read from the underlying stream before the
+    /// first message has been replayed.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    // Unwraps this `Replay`, dropping any part of the message not yet replayed.
+    pub(crate) fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Replay<S> {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, Error> {
+        if self.offset < MSG1_BYTES {
+            let read = min(buf.len(), MSG1_BYTES - self.offset);
+            buf[..read].copy_from_slice(&self.data[self.offset..self.offset + read]);
+            self.offset += read;
+            Ok(Ready(read))
+        } else {
+            self.inner.poll_read(cx, buf)
+        }
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Replay<S> {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<usize, Error> {
+        self.inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.inner.poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), Error> {
+        self.inner.poll_close(cx)
+    }
+}
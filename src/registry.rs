@@ -0,0 +1,213 @@
+// Implementation of RegistryServer, which accepts handshakes for one of several
+// tenants, selected by the network identifier the client uses.
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::mem;
+
+use futures_core::{Future, Poll};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{self, AsyncRead, AsyncWrite};
+use sodiumoxide::crypto::sign;
+use secret_handshake::NETWORK_IDENTIFIER_BYTES;
+use secret_handshake::errors::HandshakeError;
+use box_stream::BoxDuplex;
+
+use keys::EphemeralSource;
+use peek::{peek_first_message, PeekFirstMessage, FirstMessageInfo, Replay};
+use OwningServer;
+
+/// The tenants of a multi-tenant server, each with its own network identifier
+/// and server identity, for use with [`RegistryServer`](struct.RegistryServer.html).
+///
+/// Each tenant is identified by an id of type `T`, which is yielded by a
+/// successful handshake.
+pub struct TenantRegistry<T> {
+    tenants: Vec<Tenant<T>>,
+}
+
+struct Tenant<T> {
+    id: T,
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    longterm_pk: sign::PublicKey,
+    longterm_sk: sign::SecretKey,
+    ephemeral_source: RefCell<Box<dyn EphemeralSource>>,
+}
+
+impl<T> TenantRegistry<T> {
+    /// Create an empty `TenantRegistry`.
+    pub fn new() -> TenantRegistry<T> {
+        TenantRegistry { tenants: Vec::new() }
+    }
+
+    /// Add a tenant that accepts clients using `network_identifier`, with the
+    /// given longterm keys. Its ephemeral keypairs are taken from
+    /// `ephemeral_source`, once per handshake.
+    ///
+    /// If a tenant with the same network identifier was registered before, it
+    /// is replaced, and its id is returned.
+    pub fn insert<Source>(&mut self,
+                          id: T,
+                          network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                          longterm_pk: sign::PublicKey,
+                          longterm_sk: sign::SecretKey,
+                          ephemeral_source: Source)
+                          -> Option<T>
+        where Source: EphemeralSource + 'static
+    {
+        let tenant = Tenant {
+            id,
+            network_identifier,
+            longterm_pk,
+            longterm_sk,
+            ephemeral_source: RefCell::new(Box::new(ephemeral_source)),
+        };
+
+        match self.tenants
+                  .iter()
+                  .position(|old| old.network_identifier == network_identifier) {
+            Some(i) => Some(mem::replace(&mut self.tenants[i], tenant).id),
+            None => {
+                self.tenants.push(tenant);
+                None
+            }
+        }
+    }
+
+    fn select(&self, info: &FirstMessageInfo) -> Option<&Tenant<T>> {
+        self.tenants
+            .iter()
+            .find(|tenant| info.matches_network_identifier(&tenant.network_identifier))
+    }
+}
+
+impl<T> Default for TenantRegistry<T> {
+    fn default() -> TenantRegistry<T> {
+        TenantRegistry::new()
+    }
+}
+
+/// A future that accepts a secret-handshake for one of the tenants of a
+/// [`TenantRegistry`](struct.TenantRegistry.html) and then yields a channel
+/// that encrypts/decrypts all data via box-stream.
+///
+/// The network identifier is never sent in the clear, so the tenant is selected
+/// by checking the client's first handshake message against the network
+/// identifier of each tenant (see
+/// [`peek_first_message`](fn.peek_first_message.html)). The handshake then
+/// proceeds with the keys of the selected tenant.
+pub struct RegistryServer<'r, S, T: 'r> {
+    registry: &'r TenantRegistry<T>,
+    state: State<S, T>,
+}
+
+enum State<S, T> {
+    Peeking(PeekFirstMessage<S>),
+    Handshaking(Box<OwningServer<Replay<S>>>, T),
+}
+
+impl<'r, S: AsyncRead + AsyncWrite, T: Clone> RegistryServer<'r, S, T> {
+    /// Create a new `RegistryServer` to accept a connection over the given
+    /// `stream` for one of the tenants in `registry`.
+    pub fn new(stream: S, registry: &'r TenantRegistry<T>) -> RegistryServer<'r, S, T> {
+        RegistryServer {
+            registry,
+            state: State::Peeking(peek_first_message(stream)),
+        }
+    }
+}
+
+impl<'r, S: AsyncRead + AsyncWrite, T: Clone> Future for RegistryServer<'r, S, T> {
+    /// On success, the result contains the encrypted connection, the longterm
+    /// public key of the client, and the id of the selected tenant.
+    type Item = (BoxDuplex<Replay<S>>, sign::PublicKey, T);
+    /// On failure, the bytes of the handshake that were already read are lost
+    /// from the returned stream.
+    type Error = (RegistryError, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match self.state {
+                State::Peeking(ref mut peek) => {
+                    let (info, replay) = match peek.poll(cx) {
+                        Ok(Ready(peeked)) => peeked,
+                        Ok(Pending) => return Ok(Pending),
+                        Err((e, stream)) => return Err((RegistryError::IoError(e), stream)),
+                    };
+
+                    match self.registry.select(&info) {
+                        Some(tenant) => {
+                            let ephemeral = tenant.ephemeral_source.borrow_mut().next();
+                            let server =
+                                OwningServer::new_with_ephemeral_keypair(replay,
+                                                                         tenant.network_identifier,
+                                                                         tenant.longterm_pk,
+                                                                         tenant.longterm_sk.clone(),
+                                                                         ephemeral);
+                            State::Handshaking(Box::new(server), tenant.id.clone())
+                        }
+                        None => {
+                            return Err((RegistryError::UnknownNetworkIdentifier,
+                                        replay.into_inner()))
+                        }
+                    }
+                }
+                State::Handshaking(ref mut server, ref id) => {
+                    return match server.poll(cx) {
+                               Ok(Ready((duplex, client_pk))) => {
+                                   Ok(Ready((duplex, client_pk, id.clone())))
+                               }
+                               Ok(Pending) => Ok(Pending),
+                               Err((e, replay)) => Err((e.into(), replay.into_inner())),
+                           }
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+/// Errors that can occur while accepting a handshake via a
+/// [`RegistryServer`](struct.RegistryServer.html).
+#[derive(Debug)]
+pub enum RegistryError {
+    /// An io error occured during the handshake.
+    IoError(futures_io::Error),
+    /// The client's first message does not match the network identifier of any
+    /// tenant.
+    UnknownNetworkIdentifier,
+    /// The peer did not provide correct authentication.
+    CryptoError,
+}
+
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            RegistryError::IoError(ref err) => write!(f, "Handshake error: {}", err),
+            RegistryError::UnknownNetworkIdentifier => {
+                write!(f, "Handshake error: unknown network identifier")
+            }
+            RegistryError::CryptoError => write!(f, "Handshake error: crypto error"),
+        }
+    }
+}
+
+impl Error for RegistryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            RegistryError::IoError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<HandshakeError> for RegistryError {
+    fn from(err: HandshakeError) -> RegistryError {
+        match err {
+            HandshakeError::IoError(err) => RegistryError::IoError(err),
+            HandshakeError::CryptoError => RegistryError::CryptoError,
+        }
+    }
+}
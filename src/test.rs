@@ -0,0 +1,731 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::ErrorKind::{BrokenPipe, UnexpectedEof};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures_core::{Future, Poll};
+use futures_core::Async::{Ready, Pending};
+use futures_core::future::ok;
+use futures_core::never::Never;
+use futures_core::task::{Context, LocalMap, Wake, Waker};
+use futures_io::{Error, AsyncRead, AsyncWrite};
+use secret_handshake::errors::FilteringHandshakeError;
+use sodiumoxide;
+use sodiumoxide::crypto::{sign, box_, secretbox};
+
+use keys::{self, KeyError};
+use super::*;
+
+struct NoopWake;
+
+impl Wake for NoopWake {
+    fn wake(_: &Arc<NoopWake>) {}
+}
+
+type Outcome<F> = Result<<F as Future>::Item, <F as Future>::Error>;
+
+// Poll `future` once, returning its result if it is done. The pipes below never
+// need to wake a task, so the tests simply poll in a loop.
+fn poll_once<F: Future>(future: &mut F) -> Option<Outcome<F>> {
+    with_context(|cx| match future.poll(cx) {
+                     Ok(Ready(item)) => Some(Ok(item)),
+                     Ok(Pending) => None,
+                     Err(e) => Some(Err(e)),
+                 })
+}
+
+fn with_context<T, F: FnOnce(&mut Context) -> T>(f: F) -> T {
+    let waker = Waker::from(Arc::new(NoopWake));
+    let mut map = LocalMap::new();
+    f(&mut Context::without_spawn(&mut map, &waker))
+}
+
+const MAX_POLLS: usize = 1000;
+
+// Poll `a` and `b` in turns until both are done.
+fn run_both<A: Future, B: Future>(mut a: A, mut b: B) -> (Outcome<A>, Outcome<B>) {
+    let (mut a_result, mut b_result) = (None, None);
+    for _ in 0..MAX_POLLS {
+        if a_result.is_none() {
+            a_result = poll_once(&mut a);
+        }
+        if b_result.is_none() {
+            b_result = poll_once(&mut b);
+        }
+        if let (Some(_), Some(_)) = (&a_result, &b_result) {
+            return (a_result.unwrap(), b_result.unwrap());
+        }
+    }
+    panic!("futures did not complete");
+}
+
+// Poll `a` and `b` in turns until `a` is done, and return its result.
+fn run_until<A: Future, B: Future>(mut a: A, b: &mut B) -> Outcome<A> {
+    let mut b_done = false;
+    for _ in 0..MAX_POLLS {
+        if let Some(result) = poll_once(&mut a) {
+            return result;
+        }
+        if !b_done {
+            b_done = poll_once(b).is_some();
+        }
+    }
+    panic!("future did not complete");
+}
+
+// Write all of `buf` and close `writer`, which must never be pending.
+fn write_and_close<W: AsyncWrite>(writer: &mut W, buf: &[u8]) {
+    with_context(|cx| {
+        let mut offset = 0;
+        while offset < buf.len() {
+            match writer.poll_write(cx, &buf[offset..]).unwrap() {
+                Ready(written) => offset += written,
+                Pending => panic!("write is pending"),
+            }
+        }
+        assert!(writer.poll_close(cx).unwrap().is_ready());
+    })
+}
+
+// Read from `reader` until it is done or pending.
+fn read_available<R: AsyncRead>(reader: &mut R) -> Vec<u8> {
+    with_context(|cx| {
+        let mut data = Vec::new();
+        let mut buf = [0; 1000];
+        loop {
+            match reader.poll_read(cx, &mut buf).unwrap() {
+                Ready(0) | Pending => return data,
+                Ready(read) => data.extend_from_slice(&buf[..read]),
+            }
+        }
+    })
+}
+
+// One direction of an in-memory connection.
+#[derive(Default)]
+struct Channel {
+    buffer: VecDeque<u8>,
+    log: Vec<u8>, // everything ever written to the channel
+    closed: bool,
+}
+
+// A handle for inspecting the bytes written by an `End`, even after the `End`
+// has been moved into a future.
+struct Log(Rc<RefCell<Channel>>);
+
+impl Log {
+    fn bytes(&self) -> Vec<u8> {
+        self.0.borrow().log.clone()
+    }
+}
+
+// One end of an in-memory connection. Reading from an empty channel that has
+// not been closed returns `Pending`, dropping an end closes its outgoing channel.
+struct End {
+    incoming: Rc<RefCell<Channel>>,
+    outgoing: Rc<RefCell<Channel>>,
+}
+
+fn pipe() -> (End, End) {
+    let a_to_b = Rc::new(RefCell::new(Channel::default()));
+    let b_to_a = Rc::new(RefCell::new(Channel::default()));
+    (End {
+         incoming: b_to_a.clone(),
+         outgoing: a_to_b.clone(),
+     },
+     End {
+         incoming: a_to_b,
+         outgoing: b_to_a,
+     })
+}
+
+impl End {
+    fn log(&self) -> Log {
+        Log(self.outgoing.clone())
+    }
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.outgoing.borrow_mut().closed = true;
+    }
+}
+
+impl AsyncRead for End {
+    fn poll_read(&mut self, _: &mut Context, buf: &mut [u8]) -> Poll<usize, Error> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.buffer.is_empty() {
+            return Ok(if incoming.closed { Ready(0) } else { Pending });
+        }
+
+        let mut read = 0;
+        while read < buf.len() {
+            match incoming.buffer.pop_front() {
+                Some(byte) => buf[read] = byte,
+                None => break,
+            }
+            read += 1;
+        }
+        Ok(Ready(read))
+    }
+}
+
+impl AsyncWrite for End {
+    fn poll_write(&mut self, _: &mut Context, buf: &[u8]) -> Poll<usize, Error> {
+        let mut outgoing = self.outgoing.borrow_mut();
+        if outgoing.closed {
+            return Err(Error::new(BrokenPipe, "write to closed pipe"));
+        }
+        outgoing.buffer.extend(buf);
+        outgoing.log.extend(buf);
+        Ok(Ready(buf.len()))
+    }
+
+    fn poll_flush(&mut self, _: &mut Context) -> Poll<(), Error> {
+        Ok(Ready(()))
+    }
+
+    fn poll_close(&mut self, _: &mut Context) -> Poll<(), Error> {
+        self.outgoing.borrow_mut().closed = true;
+        Ok(Ready(()))
+    }
+}
+
+#[test]
+fn validate_longterm_matching() {
+    sodiumoxide::init();
+    let (pk, sk) = sign::gen_keypair();
+    assert!(keys::validate_longterm(&pk, &sk));
+}
+
+#[test]
+fn validate_longterm_mismatched() {
+    sodiumoxide::init();
+    let (_, sk) = sign::gen_keypair();
+    let (other_pk, _) = sign::gen_keypair();
+    assert!(!keys::validate_longterm(&other_pk, &sk));
+}
+
+#[test]
+fn validate_longterm_corrupted_embedded_pk() {
+    sodiumoxide::init();
+    let (pk, sk) = sign::gen_keypair();
+    let (other_pk, _) = sign::gen_keypair();
+    let mut bad_sk = sk.clone();
+    bad_sk.0[sign::SEEDBYTES..].copy_from_slice(&other_pk.0);
+
+    let msg = b"hello";
+    assert!(!sign::verify_detached(&sign::sign_detached(msg, &bad_sk), msg, &pk));
+    assert!(!keys::validate_longterm(&pk, &bad_sk));
+}
+
+#[test]
+fn longterm_from_seed_deterministic() {
+    sodiumoxide::init();
+    let seed = [42; sign::SEEDBYTES];
+    let (pk, sk) = keys::longterm_from_seed(&seed);
+    let (pk2, sk2) = keys::longterm_from_seed(&seed);
+    assert_eq!(pk, pk2);
+    assert_eq!(sk, sk2);
+    assert!(keys::validate_longterm(&pk, &sk));
+
+    let (other_pk, _) = keys::longterm_from_seed(&[43; sign::SEEDBYTES]);
+    assert_ne!(pk, other_pk);
+}
+
+const MAIN_NET_HEX: &str = "d4a1cb88a66f02f8db635ce26441cc5dac1b08420ceaac230839b755845a9ffb";
+
+#[test]
+fn network_identifier_from_hex_main_net() {
+    const MAIN_NET: [u8; NETWORK_IDENTIFIER_BYTES] = network_identifier_from_hex(MAIN_NET_HEX);
+    assert_eq!(MAIN_NET,
+               [0xd4, 0xa1, 0xcb, 0x88, 0xa6, 0x6f, 0x02, 0xf8, 0xdb, 0x63, 0x5c, 0xe2, 0x64,
+                0x41, 0xcc, 0x5d, 0xac, 0x1b, 0x08, 0x42, 0x0c, 0xea, 0xac, 0x23, 0x08, 0x39,
+                0xb7, 0x55, 0x84, 0x5a, 0x9f, 0xfb]);
+    assert_eq!(network_identifier_from_hex(&MAIN_NET_HEX.to_uppercase()), MAIN_NET);
+}
+
+#[test]
+#[should_panic]
+fn network_identifier_from_hex_invalid_digit() {
+    network_identifier_from_hex(&MAIN_NET_HEX.replace('d', "g"));
+}
+
+// Call `OwningClient::new_from_bytes` with valid keys, after applying `tamper`
+// to them, and return the error.
+fn new_from_bytes_error<F>(tamper: F) -> KeyError
+    where F: FnOnce(&mut Vec<u8>, &mut Vec<u8>, &mut Vec<u8>, &mut Vec<u8>, &mut Vec<u8>)
+{
+    sodiumoxide::init();
+    let (longterm_pk, longterm_sk) = sign::gen_keypair();
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    let (server_pk, _) = sign::gen_keypair();
+    let mut longterm_pk = longterm_pk.0.to_vec();
+    let mut longterm_sk = longterm_sk.0.to_vec();
+    let mut ephemeral_pk = ephemeral_pk.0.to_vec();
+    let mut ephemeral_sk = ephemeral_sk.0.to_vec();
+    let mut server_pk = server_pk.0.to_vec();
+    tamper(&mut longterm_pk,
+           &mut longterm_sk,
+           &mut ephemeral_pk,
+           &mut ephemeral_sk,
+           &mut server_pk);
+
+    match OwningClient::new_from_bytes(pipe().0,
+                                       [0; NETWORK_IDENTIFIER_BYTES],
+                                       &longterm_pk,
+                                       &longterm_sk,
+                                       &ephemeral_pk,
+                                       &ephemeral_sk,
+                                       &server_pk) {
+        Ok(_) => panic!("new_from_bytes accepted invalid keys"),
+        Err((e, _)) => e,
+    }
+}
+
+#[test]
+fn new_from_bytes_valid() {
+    sodiumoxide::init();
+    let (longterm_pk, longterm_sk) = sign::gen_keypair();
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    let (server_pk, _) = sign::gen_keypair();
+    assert!(OwningClient::new_from_bytes(pipe().0,
+                                         [0; NETWORK_IDENTIFIER_BYTES],
+                                         &longterm_pk.0,
+                                         &longterm_sk.0,
+                                         &ephemeral_pk.0,
+                                         &ephemeral_sk.0,
+                                         &server_pk.0)
+                    .is_ok());
+}
+
+#[test]
+fn new_from_bytes_errors() {
+    assert_eq!(new_from_bytes_error(|lpk, _, _, _, _| lpk.truncate(1)),
+               KeyError::LongtermPk);
+    assert_eq!(new_from_bytes_error(|_, lsk, _, _, _| lsk.truncate(1)),
+               KeyError::LongtermSk);
+    assert_eq!(new_from_bytes_error(|_, _, epk, _, _| epk.push(0)),
+               KeyError::EphemeralPk);
+    assert_eq!(new_from_bytes_error(|_, _, _, esk, _| esk.clear()),
+               KeyError::EphemeralSk);
+    assert_eq!(new_from_bytes_error(|_, _, _, _, spk| spk.truncate(1)),
+               KeyError::PeerLongtermPk);
+    assert_eq!(new_from_bytes_error(|lpk, _, _, _, _| lpk[0] ^= 1),
+               KeyError::LongtermMismatch);
+    assert_eq!(new_from_bytes_error(|_, _, epk, _, _| epk[0] ^= 1),
+               KeyError::EphemeralMismatch);
+}
+
+struct FixedSource {
+    keypair: Option<keys::EphemeralKeypair>,
+    queries: usize,
+}
+
+impl keys::EphemeralSource for FixedSource {
+    fn next(&mut self) -> keys::EphemeralKeypair {
+        self.queries += 1;
+        self.keypair.take().expect("queried FixedSource twice")
+    }
+}
+
+#[test]
+fn server_uses_ephemeral_source() {
+    sodiumoxide::init();
+    let network_identifier = [1; NETWORK_IDENTIFIER_BYTES];
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (client_eph_pk, client_eph_sk) = box_::gen_keypair();
+    let (server_pk, server_sk) = sign::gen_keypair();
+    let (server_eph_pk, server_eph_sk) = box_::gen_keypair();
+    let (client_end, server_end) = pipe();
+    let server_log = server_end.log();
+
+    let mut source = FixedSource {
+        keypair: Some(keys::EphemeralKeypair::from_keys(server_eph_pk, server_eph_sk)),
+        queries: 0,
+    };
+    let server = OwningServer::new_with_ephemeral_source(server_end,
+                                                         network_identifier,
+                                                         server_pk,
+                                                         server_sk,
+                                                         &mut source);
+    assert_eq!(source.queries, 1);
+
+    let client = OwningClient::new(client_end,
+                                   network_identifier,
+                                   client_pk,
+                                   client_sk,
+                                   client_eph_pk,
+                                   client_eph_sk,
+                                   server_pk);
+    let (client_result, server_result) = run_both(client, server);
+    assert!(client_result.is_ok());
+    assert_eq!(server_result.ok().unwrap().1, client_pk);
+    assert_eq!(source.queries, 1);
+
+    // The second handshake message is an hmac followed by the server's ephemeral pk.
+    assert_eq!(&server_log.bytes()[32..64], &server_eph_pk.0[..]);
+}
+
+#[test]
+fn client_owning_network_identifier_handshake() {
+    sodiumoxide::init();
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (client_eph_pk, client_eph_sk) = box_::gen_keypair();
+    let (server_pk, server_sk) = sign::gen_keypair();
+    let (server_eph_pk, server_eph_sk) = box_::gen_keypair();
+    let (client_end, server_end) = pipe();
+
+    let client = {
+        // The identifier passed to the client does not outlive this block.
+        let network_identifier = [2; NETWORK_IDENTIFIER_BYTES];
+        ClientOwningNetworkIdentifier::new(client_end,
+                                           network_identifier,
+                                           &client_pk,
+                                           &client_sk,
+                                           &client_eph_pk,
+                                           &client_eph_sk,
+                                           &server_pk)
+    };
+    let server = OwningServer::new(server_end,
+                                   [2; NETWORK_IDENTIFIER_BYTES],
+                                   server_pk,
+                                   server_sk,
+                                   server_eph_pk,
+                                   server_eph_sk);
+
+    let (client_result, server_result) = run_both(client, server);
+    assert!(client_result.is_ok());
+    assert_eq!(server_result.ok().unwrap().1, client_pk);
+}
+
+#[test]
+fn client_owning_network_identifier_drop_pending() {
+    sodiumoxide::init();
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (client_eph_pk, client_eph_sk) = box_::gen_keypair();
+    let (server_pk, _) = sign::gen_keypair();
+    let (client_end, _server_end) = pipe();
+
+    let mut client = ClientOwningNetworkIdentifier::new(client_end,
+                                                        [2; NETWORK_IDENTIFIER_BYTES],
+                                                        &client_pk,
+                                                        &client_sk,
+                                                        &client_eph_pk,
+                                                        &client_eph_sk,
+                                                        &server_pk);
+    assert!(poll_once(&mut client).is_none());
+}
+
+fn reject(_: &sign::PublicKey) -> BoxedAsyncBool<Never> {
+    Box::new(ok(false))
+}
+
+#[test]
+fn boxed_filter_rejects() {
+    sodiumoxide::init();
+    let network_identifier = [3; NETWORK_IDENTIFIER_BYTES];
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (client_eph_pk, client_eph_sk) = box_::gen_keypair();
+    let (server_pk, server_sk) = sign::gen_keypair();
+    let (server_eph_pk, server_eph_sk) = box_::gen_keypair();
+    let (client_end, server_end) = pipe();
+
+    let server = BoxedOwningServerFilter::new_boxed(server_end,
+                                                    Box::new(reject),
+                                                    network_identifier,
+                                                    server_pk,
+                                                    server_sk,
+                                                    server_eph_pk,
+                                                    server_eph_sk);
+    let mut client = OwningClient::new(client_end,
+                                       network_identifier,
+                                       client_pk,
+                                       client_sk,
+                                       client_eph_pk,
+                                       client_eph_sk,
+                                       server_pk);
+
+    match run_until(server, &mut client) {
+        Err((FilteringHandshakeError::Rejected, _)) => {}
+        Err((e, _)) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("filter did not reject the client"),
+    }
+}
+
+fn accept(_: &sign::PublicKey) -> BoxedAsyncBool<Never> {
+    Box::new(ok(true))
+}
+
+#[test]
+fn borrowing_boxed_filter_accepts() {
+    sodiumoxide::init();
+    let network_identifier = [3; NETWORK_IDENTIFIER_BYTES];
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (client_eph_pk, client_eph_sk) = box_::gen_keypair();
+    let (server_pk, server_sk) = sign::gen_keypair();
+    let (server_eph_pk, server_eph_sk) = box_::gen_keypair();
+    let (client_end, server_end) = pipe();
+
+    let server = BoxedServerFilter::new_boxed(server_end,
+                                              Box::new(accept),
+                                              &network_identifier,
+                                              &server_pk,
+                                              &server_sk,
+                                              &server_eph_pk,
+                                              &server_eph_sk);
+    let client = OwningClient::new(client_end,
+                                   network_identifier,
+                                   client_pk,
+                                   client_sk,
+                                   client_eph_pk,
+                                   client_eph_sk,
+                                   server_pk);
+
+    let (client_result, server_result) = run_both(client, server);
+    assert!(client_result.is_ok());
+    assert_eq!(server_result.ok().unwrap().1, client_pk);
+}
+
+#[test]
+fn max_plaintext_per_frame_bounds() {
+    assert_eq!(max_plaintext_per_frame(0), 0);
+    assert_eq!(max_plaintext_per_frame(FRAME_OVERHEAD - 1), 0);
+    assert_eq!(max_plaintext_per_frame(FRAME_OVERHEAD + 100), 100);
+    assert_eq!(max_plaintext_per_frame(FRAME_OVERHEAD + 4096), 4096);
+    assert_eq!(max_plaintext_per_frame(usize::MAX), 4096);
+}
+
+#[test]
+fn box_writer_reader_round_trip() {
+    sodiumoxide::init();
+    let key = secretbox::gen_key();
+    let nonce = secretbox::gen_nonce();
+    let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+    let (writer_end, reader_end) = pipe();
+    let log = writer_end.log();
+
+    let mut writer = BoxWriter::new(writer_end, key.clone(), nonce);
+    write_and_close(&mut writer, &data);
+    // Three frames, followed by the goodbye header.
+    assert_eq!(log.bytes().len(), 3 * FRAME_OVERHEAD + data.len() + FRAME_OVERHEAD);
+    assert!(log.bytes().windows(16).all(|window| window != &data[..16]));
+
+    let mut reader = BoxReader::new(reader_end, key, nonce);
+    assert_eq!(read_available(&mut reader), data);
+}
+
+#[test]
+fn peek_first_message_and_replay() {
+    sodiumoxide::init();
+    let network_identifier = [4; NETWORK_IDENTIFIER_BYTES];
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (client_eph_pk, client_eph_sk) = box_::gen_keypair();
+    let (server_pk, server_sk) = sign::gen_keypair();
+    let (server_eph_pk, server_eph_sk) = box_::gen_keypair();
+    let (client_end, server_end) = pipe();
+
+    let mut client = OwningClient::new(client_end,
+                                       network_identifier,
+                                       client_pk,
+                                       client_sk,
+                                       client_eph_pk,
+                                       client_eph_sk,
+                                       server_pk);
+    let (info, replay) = match run_until(peek_first_message(server_end), &mut client) {
+        Ok(peeked) => peeked,
+        Err((e, _)) => panic!("failed to peek: {}", e),
+    };
+    assert_eq!(info.client_ephemeral_pk(), &client_eph_pk);
+    assert!(info.matches_network_identifier(&network_identifier));
+    assert!(!info.matches_network_identifier(&[5; NETWORK_IDENTIFIER_BYTES]));
+
+    let server = OwningServer::new(replay,
+                                   network_identifier,
+                                   server_pk,
+                                   server_sk,
+                                   server_eph_pk,
+                                   server_eph_sk);
+    let (client_result, server_result) = run_both(client, server);
+    assert!(client_result.is_ok());
+    assert_eq!(server_result.ok().unwrap().1, client_pk);
+}
+
+#[test]
+fn peek_first_message_eof() {
+    let (mut client_end, server_end) = pipe();
+    write_and_close(&mut client_end, &[0; 10]);
+
+    match poll_once(&mut peek_first_message(server_end)) {
+        Some(Err((e, _))) => assert_eq!(e.kind(), UnexpectedEof),
+        _ => panic!("peeking a truncated message did not fail"),
+    }
+}
+
+#[test]
+fn broadcast_writes_and_flushes() {
+    sodiumoxide::init();
+    let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    let mut connections = Vec::new();
+    let mut readers = Vec::new();
+    let mut logs = Vec::new();
+    for i in 0..3 {
+        let (mut end, peer_end) = pipe();
+        logs.push(end.log());
+        if i == 1 {
+            // Writes to a closed end fail.
+            write_and_close(&mut end, &[]);
+        }
+        let key = secretbox::gen_key();
+        let nonce = secretbox::gen_nonce();
+        connections.push(BoxDuplex::new(end,
+                                        key.clone(),
+                                        secretbox::gen_key(),
+                                        nonce,
+                                        secretbox::gen_nonce()));
+        readers.push(BoxReader::new(peer_end, key, nonce));
+    }
+
+    let results = match poll_once(&mut broadcast(&mut connections, &data)) {
+        Some(Ok(results)) => results,
+        _ => panic!("broadcast did not complete"),
+    };
+    assert_eq!(results.len(), 3);
+    assert!(results[0].is_ok());
+    assert_eq!(results[1].as_ref().unwrap_err().kind(), BrokenPipe);
+    assert!(results[2].is_ok());
+
+    assert_eq!(read_available(&mut readers[0]), data);
+    assert!(logs[1].bytes().is_empty());
+    assert_eq!(read_available(&mut readers[2]), data);
+}
+
+#[test]
+fn ephemeral_keypair_handshake() {
+    sodiumoxide::init();
+    let network_identifier = [6; NETWORK_IDENTIFIER_BYTES];
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (server_pk, server_sk) = sign::gen_keypair();
+    let (client_end, server_end) = pipe();
+    let client_log = client_end.log();
+    let server_log = server_end.log();
+
+    let client_ephemeral = keys::EphemeralKeypair::generate();
+    let client_eph_pk = *client_ephemeral.public_key();
+    let server_ephemeral = keys::EphemeralKeypair::generate();
+    let server_eph_pk = *server_ephemeral.public_key();
+
+    let client = OwningClient::new_with_ephemeral_keypair(client_end,
+                                                          network_identifier,
+                                                          client_pk,
+                                                          client_sk,
+                                                          client_ephemeral,
+                                                          server_pk);
+    let server =
+        OwningServerFilter::new_with_ephemeral_keypair(server_end,
+                                                       move |pk: &sign::PublicKey| {
+                                                           ok::<_, Never>(*pk == client_pk)
+                                                       },
+                                                       network_identifier,
+                                                       server_pk,
+                                                       server_sk,
+                                                       server_ephemeral);
+
+    let (client_result, server_result) = run_both(client, server);
+    assert!(client_result.is_ok());
+    assert_eq!(server_result.ok().unwrap().1, client_pk);
+
+    // The first two handshake messages are an hmac followed by an ephemeral pk.
+    assert_eq!(&client_log.bytes()[32..64], &client_eph_pk.0[..]);
+    assert_eq!(&server_log.bytes()[32..64], &server_eph_pk.0[..]);
+}
+
+#[test]
+fn registry_selects_tenant() {
+    sodiumoxide::init();
+    let (a_pk, a_sk) = sign::gen_keypair();
+    let (b_pk, b_sk) = sign::gen_keypair();
+    let mut registry = TenantRegistry::new();
+    assert!(registry
+                .insert("a", [7; NETWORK_IDENTIFIER_BYTES], a_pk, a_sk, keys::GenKeypair)
+                .is_none());
+    assert!(registry
+                .insert("b", [8; NETWORK_IDENTIFIER_BYTES], b_pk, b_sk, keys::GenKeypair)
+                .is_none());
+
+    for &(network_identifier, server_pk, tenant) in
+        &[([7; NETWORK_IDENTIFIER_BYTES], a_pk, "a"), ([8; NETWORK_IDENTIFIER_BYTES], b_pk, "b")] {
+        let (client_pk, client_sk) = sign::gen_keypair();
+        let (client_eph_pk, client_eph_sk) = box_::gen_keypair();
+        let (client_end, server_end) = pipe();
+
+        let client = OwningClient::new(client_end,
+                                       network_identifier,
+                                       client_pk,
+                                       client_sk,
+                                       client_eph_pk,
+                                       client_eph_sk,
+                                       server_pk);
+        let server = RegistryServer::new(server_end, &registry);
+
+        let (client_result, server_result) = run_both(client, server);
+        assert!(client_result.is_ok());
+        let (_, peer_pk, id) = server_result.ok().unwrap();
+        assert_eq!(peer_pk, client_pk);
+        assert_eq!(id, tenant);
+    }
+}
+
+#[test]
+fn registry_unknown_network_identifier() {
+    sodiumoxide::init();
+    let (server_pk, server_sk) = sign::gen_keypair();
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (client_eph_pk, client_eph_sk) = box_::gen_keypair();
+    let (client_end, server_end) = pipe();
+    let mut registry = TenantRegistry::new();
+    registry.insert(0, [7; NETWORK_IDENTIFIER_BYTES], server_pk, server_sk, keys::GenKeypair);
+
+    let mut client = OwningClient::new(client_end,
+                                       [9; NETWORK_IDENTIFIER_BYTES],
+                                       client_pk,
+                                       client_sk,
+                                       client_eph_pk,
+                                       client_eph_sk,
+                                       server_pk);
+    match run_until(RegistryServer::new(server_end, &registry), &mut client) {
+        Err((RegistryError::UnknownNetworkIdentifier, _)) => {}
+        Err((e, _)) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("accepted an unknown network identifier"),
+    }
+}
+
+#[test]
+fn connection_with_context() {
+    let (end, mut peer_end) = pipe();
+    let mut connection = ConnectionWithContext::new(end, vec![1]);
+
+    write_and_close(&mut peer_end, b"ping");
+    assert_eq!(read_available(&mut connection), b"ping");
+    write_and_close(&mut connection, b"pong");
+    assert_eq!(read_available(&mut peer_end), b"pong");
+
+    connection.context_mut().push(2);
+    assert_eq!(connection.context(), &vec![1, 2]);
+
+    assert_eq!(connection.get_ref().log().bytes(), b"pong");
+    let (end, context) = connection.into_parts();
+    assert_eq!(end.log().bytes(), b"pong");
+    assert_eq!(context, vec![1, 2]);
+}
+
+#[test]
+fn crypto_info_matches_constants() {
+    sodiumoxide::init();
+    let info = crypto_info();
+    assert_eq!(info.box_header_bytes, FRAME_OVERHEAD);
+    assert_eq!(info.network_identifier_bytes, NETWORK_IDENTIFIER_BYTES);
+    assert_eq!(info.max_box_body_bytes, max_plaintext_per_frame(usize::MAX));
+    assert!(!info.libsodium_version.is_empty());
+}